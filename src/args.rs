@@ -1,10 +1,48 @@
 // Copyright (C) 2025 Daniel Mueller <deso@posteo.net>
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use std::path::PathBuf;
+
 use clap::Parser;
 
 
 /// A program/daemon sending notifications when MPD plays a new song.
 #[derive(Debug, Parser)]
 #[command(version = env!("VERSION"))]
-pub struct Args {}
+pub struct Args {
+  /// The MPD configuration file to use instead of searching the
+  /// well-known locations.
+  #[arg(long)]
+  pub config: Option<PathBuf>,
+  /// The host (or socket path) of the MPD instance to connect to.
+  /// Defaults to `$MPD_HOST`, then the configured `bind_to_address`.
+  #[arg(long)]
+  pub host: Option<String>,
+  /// The port of the MPD instance to connect to. Defaults to
+  /// `$MPD_PORT`, then the configured `port`.
+  #[arg(long)]
+  pub port: Option<u16>,
+  /// The password to authenticate with. Defaults to the configured
+  /// `password`.
+  #[arg(long)]
+  pub password: Option<String>,
+  /// The notification timeout, in milliseconds.
+  #[arg(long, default_value_t = 5000)]
+  pub timeout: i32,
+  /// Query the current song once, send a single notification for it
+  /// (none if nothing is playing), and exit.
+  #[arg(long)]
+  pub oneshot: bool,
+  /// The template for the notification summary. Supported placeholders
+  /// are `{artist}`, `{title}`, `{album}`, `{date}`, `{track}`, and
+  /// `{file}`; missing tags fall back to the file's basename.
+  #[arg(long, default_value = "{artist} – {title}")]
+  pub summary_format: String,
+  /// The template for the notification body; see `--summary-format`
+  /// for the supported placeholders.
+  #[arg(long, default_value = "{album} ({date})")]
+  pub body_format: String,
+  /// The separator inserted between multiple artists.
+  #[arg(long, default_value = ", ")]
+  pub artist_separator: String,
+}
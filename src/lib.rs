@@ -9,50 +9,294 @@ mod mpd;
 
 use std::collections::HashMap;
 use std::env::args_os;
+use std::env::temp_dir;
+use std::env::var_os;
+use std::fs;
 use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::thread::sleep;
+use std::thread::spawn;
 use std::time::Duration;
 
-use anyhow::ensure;
 use anyhow::Context as _;
 use anyhow::Result;
 
 use clap::error::ErrorKind;
 use clap::Parser as _;
 
-use inotify::Inotify;
-use inotify::WatchMask;
-
 use zbus::blocking::connection::Builder as ConnectionBuilder;
+use zbus::blocking::MessageIterator;
+use zbus::blocking::Proxy;
 use zbus::names::WellKnownName;
 use zbus::zvariant::Value;
 use zbus::Address;
 
 use crate::args::Args;
+use crate::mpd::client::Client;
+use crate::mpd::client::Endpoint;
+use crate::mpd::client::Song;
 
 
-fn send_notification(summary: &str) -> Result<()> {
-  let appname = env!("CARGO_PKG_NAME");
-  let replaces_id = 1u32;
-  let icon = "";
-  let body = "";
-  let hints = HashMap::<&str, Value>::new();
-  // 5s.
-  let timeout = 5000i32;
+/// Check whether `path` is a recognized cover-art file (`cover.*`,
+/// `folder.*`, or `front.{jpg,jpeg,png,gif}`).
+fn is_cover_file(path: &Path) -> bool {
+  let stem = path
+    .file_stem()
+    .and_then(|stem| stem.to_str())
+    .map(str::to_ascii_lowercase);
+  let ext = path
+    .extension()
+    .and_then(|ext| ext.to_str())
+    .map(str::to_ascii_lowercase);
+  match (stem.as_deref(), ext.as_deref()) {
+    (Some("cover" | "folder"), Some(_)) => true,
+    (Some("front"), Some("jpg" | "jpeg" | "png" | "gif")) => true,
+    _ => false,
+  }
+}
+
+
+/// Resolver and cache for album art.
+///
+/// Art is looked up first by asking MPD for the song's embedded or
+/// adjacent picture and, failing that, by scanning the song's directory
+/// under `music_directory` for a well-known cover file. MPD-provided
+/// art is per song, so it is cached per file; the filesystem scan is
+/// shared by a whole album, so it is cached per directory.
+struct CoverArt {
+  /// The configured music directory, used for the local lookup.
+  music_directory: Option<PathBuf>,
+  /// The directory MPD-provided art is written to.
+  scratch_dir: PathBuf,
+  /// Cache of MPD-provided art, keyed by the song's file.
+  file_cache: HashMap<String, Option<PathBuf>>,
+  /// Cache of filesystem scans, keyed by the song's directory.
+  dir_cache: HashMap<String, Option<PathBuf>>,
+}
+
+impl CoverArt {
+  fn new(music_directory: Option<PathBuf>) -> Self {
+    Self {
+      music_directory,
+      scratch_dir: temp_dir().join(env!("CARGO_PKG_NAME")),
+      file_cache: HashMap::new(),
+      dir_cache: HashMap::new(),
+    }
+  }
+
+  /// Resolve the cover art for `song`, returning a path suitable for
+  /// the freedesktop `image-path` hint.
+  fn resolve(&mut self, client: &mut Client, song: &Song) -> Option<PathBuf> {
+    self
+      .mpd_art(client, &song.file)
+      .or_else(|| self.local_art(&song.file))
+  }
+
+  /// Resolve the song's embedded/adjacent art via MPD, cached per file
+  /// since such art is specific to the individual song. This also works
+  /// for a remote daemon whose music directory is not visible locally.
+  fn mpd_art(&mut self, client: &mut Client, file: &str) -> Option<PathBuf> {
+    if let Some(cached) = self.file_cache.get(file) {
+      return cached.clone()
+    }
+
+    let mut resolved = None;
+    for fetch in [Client::read_picture, Client::albumart] {
+      if let Ok(Some(data)) = fetch(client, file) {
+        if let Some(path) = self.store(file, &data) {
+          resolved = Some(path);
+          break
+        }
+      }
+    }
+    let _prev = self.file_cache.insert(file.to_string(), resolved.clone());
+    resolved
+  }
 
+  /// Scan the song's directory under `music_directory` for a well-known
+  /// cover file, cached per directory since it is shared by the album.
+  fn local_art(&mut self, file: &str) -> Option<PathBuf> {
+    let dir = file
+      .rsplit_once('/')
+      .map(|(dir, _)| dir)
+      .unwrap_or_default()
+      .to_string();
+
+    if let Some(cached) = self.dir_cache.get(&dir) {
+      return cached.clone()
+    }
+
+    let resolved = self.scan_directory(&dir);
+    let _prev = self.dir_cache.insert(dir, resolved.clone());
+    resolved
+  }
+
+  fn scan_directory(&self, dir: &str) -> Option<PathBuf> {
+    let directory = self.music_directory.as_ref()?.join(dir);
+    let entries = fs::read_dir(directory).ok()?;
+    entries
+      .filter_map(Result::ok)
+      .map(|entry| entry.path())
+      .find(|path| is_cover_file(path))
+  }
+
+  /// Persist MPD-provided art to a per-file scratch file and return its
+  /// path.
+  fn store(&self, file: &str, data: &[u8]) -> Option<PathBuf> {
+    let () = fs::create_dir_all(&self.scratch_dir).ok()?;
+    let name = file.replace('/', "_");
+    let name = if name.is_empty() { "cover" } else { &name };
+    let path = self.scratch_dir.join(name);
+    let () = fs::write(&path, data).ok()?;
+    Some(path)
+  }
+}
+
+
+/// The freedesktop notification service we talk to.
+const NOTIFY_SERVICE: &str = "org.freedesktop.Notifications";
+const NOTIFY_PATH: &str = "/org/freedesktop/Notifications";
+
+
+/// Establish a blocking D-Bus session connection.
+fn session_connection() -> Result<zbus::blocking::Connection> {
   let address = Address::session().context("failed to get D-Bus session address")?;
-  let connection = ConnectionBuilder::address(address.clone())
+  ConnectionBuilder::address(address.clone())
     .with_context(|| format!("failed to create connection builder for address {address}"))?
     .build()
-    .with_context(|| format!("failed to establish D-Bus session connection to {address}"))?;
+    .with_context(|| format!("failed to establish D-Bus session connection to {address}"))
+}
+
+/// Query the notification server and report whether it advertises the
+/// `actions` capability.
+fn actions_supported() -> Result<bool> {
+  let connection = session_connection()?;
+  let proxy = Proxy::new(&connection, NOTIFY_SERVICE, NOTIFY_PATH, NOTIFY_SERVICE)
+    .context("failed to create notification proxy")?;
+  let capabilities = proxy
+    .call::<_, _, Vec<String>>("GetCapabilities", &())
+    .context("failed to query notification server capabilities")?;
+  Ok(capabilities.iter().any(|capability| capability == "actions"))
+}
 
-  let bus = WellKnownName::from_static_str_unchecked("org.freedesktop.Notifications");
+/// Translate an advertised notification action key into the MPD command
+/// that carries it out.
+fn action_to_command(action: &str) -> Option<&'static str> {
+  match action {
+    "next" => Some("next"),
+    "prev" => Some("previous"),
+    // An argument-less `pause` toggles between playing and paused.
+    "playpause" => Some("pause"),
+    _ => None,
+  }
+}
+
+/// Connect to MPD at `endpoint`, authenticating with `password` if one
+/// is provided.
+fn connect(endpoint: &Endpoint, password: Option<&str>) -> Result<Client> {
+  let mut client = Client::connect(endpoint)?;
+  if let Some(password) = password {
+    let () = client
+      .password(password)
+      .context("failed to authenticate with MPD")?;
+  }
+  Ok(client)
+}
+
+/// Listen for `ActionInvoked`/`NotificationClosed` signals and forward
+/// invoked actions to MPD as playback commands.
+///
+/// Only actions targeting the most recently sent notification (tracked
+/// via `current_id`) are honored; `NotificationClosed` clears that id so
+/// a dismissed notification stops controlling playback.
+fn listen_for_actions(
+  endpoint: Endpoint,
+  password: Option<String>,
+  current_id: Arc<AtomicU32>,
+) -> Result<()> {
+  let connection = session_connection()?;
+  let proxy = Proxy::new(&connection, NOTIFY_SERVICE, NOTIFY_PATH, NOTIFY_SERVICE)
+    .context("failed to create notification proxy")?;
+  // Keep the subscriptions alive for as long as we read messages; they
+  // install the match rules that let the signals reach us.
+  let _invoked = proxy
+    .receive_signal("ActionInvoked")
+    .context("failed to subscribe to ActionInvoked")?;
+  let _closed = proxy
+    .receive_signal("NotificationClosed")
+    .context("failed to subscribe to NotificationClosed")?;
+
+  for message in MessageIterator::from(connection) {
+    let message = message.context("failed to receive D-Bus signal")?;
+    let member = message.header().member().map(|member| member.to_string());
+    match member.as_deref() {
+      Some("ActionInvoked") => {
+        let (id, action) = message
+          .body()
+          .deserialize::<(u32, String)>()
+          .context("failed to deserialize ActionInvoked signal")?;
+        if id != current_id.load(Ordering::Relaxed) {
+          continue
+        }
+        if let Some(command) = action_to_command(&action) {
+          match connect(&endpoint, password.as_deref()) {
+            Ok(mut client) => {
+              if let Err(err) = client.command(command) {
+                eprintln!("failed to run MPD command `{command}`: {err:?}");
+              }
+            },
+            Err(err) => eprintln!("failed to connect to MPD for action `{action}`: {err:?}"),
+          }
+        }
+      },
+      Some("NotificationClosed") => {
+        let (id, _reason) = message
+          .body()
+          .deserialize::<(u32, u32)>()
+          .context("failed to deserialize NotificationClosed signal")?;
+        let _prev = current_id.compare_exchange(id, 0, Ordering::Relaxed, Ordering::Relaxed);
+      },
+      _ => (),
+    }
+  }
+  Ok(())
+}
+
+
+fn send_notification(
+  summary: &str,
+  body: &str,
+  image: Option<&Path>,
+  actions: bool,
+  timeout: i32,
+) -> Result<u32> {
+  let appname = env!("CARGO_PKG_NAME");
+  let replaces_id = 1u32;
+  let icon = "";
+  let mut hints = HashMap::<&str, Value>::new();
+  if let Some(image) = image {
+    let _prev = hints.insert("image-path", Value::from(image.to_string_lossy().into_owned()));
+  }
+  // Advertise the playback controls only once the server has confirmed
+  // it supports actions. The array alternates action key and label.
+  let actions: &[&str] = if actions {
+    &["prev", "Previous", "playpause", "Play/Pause", "next", "Next"]
+  } else {
+    &[]
+  };
+
+  let connection = session_connection()?;
+
+  let bus = WellKnownName::from_static_str_unchecked(NOTIFY_SERVICE);
   let destination = Some(bus);
-  let path = "/org/freedesktop/Notifications";
-  let interface = "org.freedesktop.Notifications";
+  let path = NOTIFY_PATH;
+  let interface = NOTIFY_SERVICE;
   let method = "Notify";
 
-  let _msg_id = connection
+  let msg_id = connection
     .call_method(
       destination.clone(),
       path,
@@ -64,7 +308,7 @@ fn send_notification(summary: &str) -> Result<()> {
         icon,
         summary,
         body,
-        [""; 0].as_slice(),
+        actions,
         &hints,
         timeout,
       ),
@@ -73,12 +317,47 @@ fn send_notification(summary: &str) -> Result<()> {
     .body()
     .deserialize::<u32>()
     .context("failed to deserialize D-Bus message body")?;
-  Ok(())
+  Ok(msg_id)
+}
+
+/// Serve notifications over an established MPD connection.
+///
+/// The function queries the current song once up front and then blocks
+/// in MPD's `idle` command, re-querying whenever the player subsystem
+/// reports a change. It returns only on error: when the connection is
+/// lost or a notification cannot be sent.
+fn serve(
+  client: &mut Client,
+  args: &Args,
+  cover: &mut CoverArt,
+  actions: bool,
+  current_id: &AtomicU32,
+  previous: &mut Option<Song>,
+) -> Result<()> {
+  loop {
+    let current = client
+      .current_song()
+      .context("failed to query current song from MPD")?;
+    if current != *previous {
+      if let Some(song) = &current {
+        let summary = song.render(&args.summary_format, &args.artist_separator);
+        let body = song.render(&args.body_format, &args.artist_separator);
+        let image = cover.resolve(client, song);
+        let id = send_notification(&summary, &body, image.as_deref(), actions, args.timeout)
+          .context("failed to send DBus notification")?;
+        let () = current_id.store(id, Ordering::Relaxed);
+      }
+      *previous = current;
+    }
+    let () = client
+      .idle("player")
+      .context("failed to wait for MPD player change")?;
+  }
 }
 
 /// Run the program.
 pub fn run() -> Result<()> {
-  let _args = match Args::try_parse_from(args_os()) {
+  let args = match Args::try_parse_from(args_os()) {
     Ok(args) => args,
     Err(err) => match err.kind() {
       ErrorKind::DisplayHelp | ErrorKind::DisplayVersion => {
@@ -89,51 +368,95 @@ pub fn run() -> Result<()> {
     },
   };
 
-  let config_path = mpd::find_config()?;
+  let config_path = match &args.config {
+    Some(config) => config.clone(),
+    None => mpd::find_config()?,
+  };
   let config = mpd::parse_config_file(&config_path).context("failed to parse MPD config file")?;
-  let state_file = config
-    .get("state_file")
-    .context("MPD configuration does not specify `state_file`")?;
 
-  let mut inotify = Inotify::init().context("failed to create file watcher")?;
-  let mut buffer = [0u8; 1024];
-  let mut previous = None;
-  loop {
-    let _descriptor = inotify
-      .watches()
-      .add(state_file, WatchMask::CREATE)
-      .with_context(|| format!("failed to add file watch for `{state_file}`"))?;
-
-    let mut events = inotify
-      .read_events_blocking(&mut buffer)
-      .with_context(|| format!("failed to wait for inotify event on `{state_file}`"))?;
-
-    if events.next().is_some() {
-      let path = Path::new(state_file);
-      let mut i = 0;
-      // TODO: It is unclear why the file would not exist shortly after
-      //       we receive a creation event, but that is what we see
-      //       frequently. There shouldn't be any races, assuming it's
-      //       only written a single time. Need to figure out what is
-      //       going on.
-      while !path.exists() {
-        i += 1;
-        ensure!(
-          i < 500,
-          "failed to find MPD state file at `{}`",
-          path.display()
-        );
-        let () = sleep(Duration::from_millis(1));
+  // Command line flags take precedence over the `MPD_HOST`/`MPD_PORT`
+  // environment variables, which in turn override the configured
+  // defaults.
+  let host = args
+    .host
+    .clone()
+    .or_else(|| var_os("MPD_HOST").map(|host| host.to_string_lossy().into_owned()))
+    .or_else(|| config.bind_to_address().map(str::to_string));
+  let port = args
+    .port
+    .or_else(|| {
+      var_os("MPD_PORT").and_then(|port| port.to_string_lossy().parse().ok())
+    })
+    .or_else(|| config.port());
+  let endpoint = Endpoint::from_config(host.as_deref(), port);
+  let password = args
+    .password
+    .clone()
+    .or_else(|| config.password().map(str::to_string));
+  let mut cover = CoverArt::new(config.music_directory().map(PathBuf::from));
+
+  // In one-shot mode we query the current song exactly once, send a
+  // single notification if a song is playing (none otherwise), and
+  // exit; no interactive actions are wired up.
+  if args.oneshot {
+    let mut client = connect(&endpoint, password.as_deref())?;
+    if let Some(song) = client
+      .current_song()
+      .context("failed to query current song from MPD")?
+    {
+      let summary = song.render(&args.summary_format, &args.artist_separator);
+      let body = song.render(&args.body_format, &args.artist_separator);
+      let image = cover.resolve(&mut client, &song);
+      let _id = send_notification(&summary, &body, image.as_deref(), false, args.timeout)
+        .context("failed to send DBus notification")?;
+    }
+    return Ok(())
+  }
+
+  // Only advertise interactive actions if the notification server
+  // understands them, and in that case spawn a listener that relays
+  // invoked actions back to MPD.
+  let actions = actions_supported().unwrap_or_else(|err| {
+    eprintln!("failed to query notification capabilities: {err:?}");
+    false
+  });
+  let current_id = Arc::new(AtomicU32::new(0));
+  if actions {
+    let endpoint = endpoint.clone();
+    let password = password.clone();
+    let current_id = Arc::clone(&current_id);
+    let _handle = spawn(move || {
+      if let Err(err) = listen_for_actions(endpoint, password, current_id) {
+        eprintln!("notification action listener terminated: {err:?}");
       }
+    });
+  }
 
-      let current =
-        mpd::parse_state_file_current(path).context("failed to parse MPD state file")?;
-      if current != previous {
-        if let Some(current) = &current {
-          let () = send_notification(current).context("failed to send DBus notification")?;
+  // Reconnect with an exponential backoff whenever the socket drops so
+  // that a restarting (or temporarily unreachable) daemon is handled
+  // gracefully.
+  let mut previous = None;
+  let mut backoff = Duration::from_secs(1);
+  let max_backoff = Duration::from_secs(30);
+  loop {
+    match connect(&endpoint, password.as_deref()) {
+      Ok(mut client) => {
+        backoff = Duration::from_secs(1);
+        if let Err(err) = serve(
+          &mut client,
+          &args,
+          &mut cover,
+          actions,
+          &current_id,
+          &mut previous,
+        ) {
+          eprintln!("lost connection to MPD: {err:?}");
         }
-      }
-      previous = current;
+      },
+      Err(err) => eprintln!("failed to connect to MPD: {err:?}"),
     }
+
+    let () = sleep(backoff);
+    backoff = (backoff * 2).min(max_backoff);
   }
 }
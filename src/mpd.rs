@@ -1,13 +1,14 @@
 // Copyright (C) 2025 Daniel Mueller <deso@posteo.net>
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+pub(crate) mod client;
+
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::BufRead;
 use std::io::BufReader;
 use std::path::Path;
 use std::path::PathBuf;
-use std::str::FromStr as _;
 
 use anyhow::bail;
 use anyhow::Context as _;
@@ -42,12 +43,71 @@ pub(crate) fn find_config() -> Result<PathBuf> {
 }
 
 
-fn parse_config<R>(mut reader: R) -> Result<HashMap<String, String>>
+/// A single scope of an MPD configuration: the top level or a
+/// `name { ... }` block.
+///
+/// Settings are stored as lists because MPD permits a key to appear
+/// more than once (e.g. multiple `bind_to_address` lines); nested
+/// blocks are grouped by their type name (e.g. several `audio_output`
+/// blocks).
+#[derive(Debug, Default)]
+pub(crate) struct Block {
+  settings: HashMap<String, Vec<String>>,
+  blocks: HashMap<String, Vec<Block>>,
+}
+
+impl Block {
+  /// Retrieve the first value recorded for `key`, if any.
+  fn get(&self, key: &str) -> Option<&str> {
+    self.settings.get(key).and_then(|values| values.first()).map(String::as_str)
+  }
+
+  /// Retrieve all values recorded for `key`.
+  #[cfg(test)]
+  fn get_all(&self, key: &str) -> &[String] {
+    self.settings.get(key).map(Vec::as_slice).unwrap_or_default()
+  }
+}
+
+
+/// A parsed MPD configuration.
+#[derive(Debug)]
+pub(crate) struct Config {
+  root: Block,
+}
+
+impl Config {
+  /// The configured `music_directory`, if any.
+  pub(crate) fn music_directory(&self) -> Option<&str> {
+    self.root.get("music_directory")
+  }
+
+  /// The first configured `bind_to_address`, if any.
+  pub(crate) fn bind_to_address(&self) -> Option<&str> {
+    self.root.get("bind_to_address")
+  }
+
+  /// The configured `port`, parsed as a number.
+  pub(crate) fn port(&self) -> Option<u16> {
+    self.root.get("port").and_then(|port| port.parse().ok())
+  }
+
+  /// The configured `password`, if any.
+  pub(crate) fn password(&self) -> Option<&str> {
+    self.root.get("password")
+  }
+}
+
+
+fn parse_config<R>(mut reader: R) -> Result<Config>
 where
   R: BufRead,
 {
+  let home = home_dir();
+  // A stack of the `(name, block)` scopes currently being parsed. The
+  // bottom-most entry is the unnamed top-level scope.
+  let mut stack = vec![(String::new(), Block::default())];
   let mut line = String::new();
-  let mut values = HashMap::new();
   while let Ok(len) = reader.read_line(&mut line) {
     if len == 0 {
       break
@@ -61,69 +121,64 @@ where
     } else {
       s
     };
+    let s = s.trim();
+
+    if s.is_empty() {
+      let () = line.clear();
+      continue
+    }
 
-    if let Some((key, mut value)) = s.split_once(|c: char| c.is_ascii_whitespace()) {
+    if s == "}" {
+      // Close the current block and attach it to its parent, keyed by
+      // its type, preserving repeated blocks as a list.
+      let (name, block) = stack
+        .pop()
+        .filter(|_| !stack.is_empty())
+        .context("encountered unmatched `}` in MPD configuration")?;
+      let (_, parent) = stack
+        .last_mut()
+        .context("encountered unmatched `}` in MPD configuration")?;
+      let () = parent.blocks.entry(name).or_default().push(block);
+    } else if let Some(name) = s.strip_suffix('{') {
+      // Open a new block, e.g. `audio_output {`.
+      let () = stack.push((name.trim().to_string(), Block::default()));
+    } else if let Some((key, mut value)) = s.split_once(|c: char| c.is_ascii_whitespace()) {
       // Could use `str::trim_matches` here, but it removes stuff
       // repeatedly.
       value = value.trim();
       value = value.strip_prefix('"').unwrap_or(value);
       value = value.strip_suffix('"').unwrap_or(value);
-      let _prev = values.insert(key.to_string(), value.to_string());
+      let value = expand_tilde(value, home.as_deref());
+
+      let (_, block) = stack.last_mut().unwrap();
+      // Preserve repeated keys (e.g. multiple `bind_to_address`) as a
+      // list rather than overwriting.
+      let () = block.settings.entry(key.to_string()).or_default().push(value);
     }
     let () = line.clear();
   }
-  Ok(values)
-}
 
-/// Parse the MPD configuration.
-pub(crate) fn parse_config_file(path: &Path) -> Result<HashMap<String, String>> {
-  let file =
-    File::open(path).with_context(|| format!("failed to open file `{}`", path.display()))?;
-  parse_config(BufReader::new(file))
+  let (_, root) = stack
+    .pop()
+    .filter(|_| stack.is_empty())
+    .context("encountered unterminated block in MPD configuration")?;
+  Ok(Config { root })
 }
 
-
-fn parse_state<R>(mut reader: R) -> Result<String>
-where
-  R: BufRead,
-{
-  let mut line = String::new();
-  // The index of the currently playing song.
-  let mut current_prefix = None;
-  while let Ok(len) = reader.read_line(&mut line) {
-    if len == 0 {
-      break
-    }
-
-    match &current_prefix {
-      // If we don't have a current song index yet, keep looking for it.
-      None => {
-        if let Some(current) = line.strip_prefix("current:") {
-          let current = usize::from_str(current.trim())
-            .with_context(|| format!("failed to parse current song index `{current}`"))?;
-          current_prefix = Some(format!("{current}:"));
-        }
-      },
-      // If we have a prefix then check each line for a match.
-      Some(current_prefix) => {
-        if let Some(current) = line.strip_prefix(current_prefix) {
-          // Once we found the current song we can stop immediately.
-          return Ok(current.trim().to_string())
-        }
-      },
-    }
-    let () = line.clear();
+/// Expand a leading `~/` in a path-like value against the home
+/// directory, leaving everything else untouched.
+fn expand_tilde(value: &str, home: Option<&Path>) -> String {
+  match (value.strip_prefix("~/"), home) {
+    (Some(rest), Some(home)) => home.join(rest).to_string_lossy().into_owned(),
+    _ => value.to_string(),
   }
-
-  bail!("failed to find currently playing song in MPD state file contents")
 }
 
-
-/// Parse the MPD state file, retrieving the current song.
-pub(crate) fn parse_state_file_current(path: &Path) -> Result<String> {
+/// Parse the MPD configuration.
+pub(crate) fn parse_config_file(path: &Path) -> Result<Config> {
   let file =
     File::open(path).with_context(|| format!("failed to open file `{}`", path.display()))?;
-  parse_state(BufReader::new(file))
+  parse_config(BufReader::new(file))
 }
 
 
@@ -209,54 +264,50 @@ input {
 }
 "##;
     let reader = BufReader::new(Cursor::new(conf));
-    let values = parse_config(reader).unwrap();
-    assert_eq!(values.get("state_file").unwrap(), "/var/lib/mpd/state");
+    let config = parse_config(reader).unwrap();
+    assert_eq!(config.root.get("state_file"), Some("/var/lib/mpd/state"));
+    assert_eq!(config.music_directory(), Some("/var/lib/mpd/music"));
+    // The `input { plugin "curl" }` block must not leak into the
+    // top-level scope.
+    assert_eq!(config.root.get("plugin"), None);
   }
 
-  /// Check that we can extract the name of the currently playing file
-  /// from an MPD state file.
+  /// Check that blocks, repeated keys, and `~/` expansion are handled.
   #[test]
-  fn state_file_parsing() {
-    let state = r#"
-sw_volume: 80
-audio_device_state:1:My ALSA EQ
-state: play
-current: 6
-time: 18.372000
-random: 1
-repeat: 1
-single: 0
-consume: 0
-crossfade: 0
-mixrampdb: 0.000000
-mixrampdelay: -1.000000
-playlist_begin
-0:by-artist/various/21ror_-_talk_about.opus
-1:by-artist/various/24kgoldn_-_mood_(feat._iann_dior).opus
-2:by-artist/various/3_doors_down_-_kryptonite.opus
-3:by-artist/various/ace_frehley_-_new_york_groove.opus
-4:by-artist/various/adele_-_hello.opus
-5:by-artist/various/adele_-_rolling_in_the_deep.m4a
-6:by-artist/various/adele_-_someone_like_you.opus
-7:by-artist/various/afroman_-_because_i_got_high.opus
-8:by-artist/various/akon_-_i_wanna_love_you_feat._snoop_dogg.opus
-9:by-artist/various/akon_-_smack_that_feat._eminem.opus
-10:by-artist/various/alan_walker_-_faded.opus
-11:by-artist/various/alessia_cara_-_scars_to_your_beautiful.opus
-12:by-artist/various/alesso_-_heroes_(we_could_be)_(ft._tove_lo).aac
-13:by-artist/various/alexandra_stan_-_mr._saxobeat.opus
-14:by-artist/various/alex_metric_&_jacques_lu_cont_-_safe_with_you_(feat_malin).aac
-15:by-artist/various/alicia_keys_-_girl_on_fire.opus
-16:by-artist/various/all_about_she_-_higher_(free).aac
-17:by-artist/various/alvyn_&_jstn_dmnd_-_sky_bri.opus
-18:by-artist/various/arcando_&_maazel_-_to_be_loved_(feat._salvo).opus
-19:by-artist/various/ariana_grande_-_7_rings.opus
-20:by-artist/various/ariana_grande_-_side_to_side_(feat._nicki_minaj).opus
-playlist_end
-"#;
+  fn config_blocks_and_lists() {
+    let conf = r#"
+bind_to_address "localhost"
+bind_to_address "/run/mpd/socket"
+port "6600"
+sticker_file "~/.mpd/sticker.sql"
+
+audio_output {
+        type "alsa"
+        name "My ALSA Device"
+}
 
-    let reader = BufReader::new(Cursor::new(state));
-    let current = parse_state(reader).unwrap();
-    assert_eq!(current, "by-artist/various/adele_-_someone_like_you.opus");
+audio_output {
+        type "pulse"
+        name "My Pulse Output"
+}
+"#;
+    let reader = BufReader::new(Cursor::new(conf));
+    let config = parse_config(reader).unwrap();
+
+    assert_eq!(config.bind_to_address(), Some("localhost"));
+    // Repeated keys are preserved as a list rather than overwritten.
+    assert_eq!(
+      config.root.get_all("bind_to_address"),
+      ["localhost".to_string(), "/run/mpd/socket".to_string()]
+    );
+    assert_eq!(config.port(), Some(6600));
+    assert_eq!(config.root.blocks.get("audio_output").unwrap().len(), 2);
+
+    if let Some(home) = home_dir() {
+      assert_eq!(
+        config.root.get("sticker_file"),
+        Some(home.join(".mpd/sticker.sql").to_string_lossy().as_ref())
+      );
+    }
   }
 }
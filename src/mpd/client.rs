@@ -0,0 +1,411 @@
+// Copyright (C) 2025 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! A minimal client speaking MPD's line-based protocol.
+//!
+//! MPD exposes a text protocol over TCP or a Unix domain socket. On
+//! connect the daemon greets us with a line `OK MPD <version>`.
+//! Commands are terminated by a newline and each response is a sequence
+//! of `key: value` lines terminated either by a lone `OK` line on
+//! success or an `ACK [code@idx] {cmd} message` line on failure.
+
+use std::io::BufRead as _;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Result;
+
+
+/// The address of the MPD instance to talk to.
+///
+/// A `bind_to_address` value starting with a slash denotes a Unix
+/// domain socket, anything else a host to be paired with a port.
+#[derive(Clone, Debug)]
+pub(crate) enum Endpoint {
+  /// A host and port to reach MPD over TCP.
+  Tcp(String, u16),
+  /// The path of a Unix domain socket.
+  Unix(String),
+}
+
+impl Endpoint {
+  /// Derive an endpoint from a `bind_to_address`/`port` pair as found
+  /// in an MPD configuration.
+  pub(crate) fn from_config(address: Option<&str>, port: Option<u16>) -> Self {
+    let address = address.unwrap_or("localhost");
+    if address.starts_with('/') {
+      Endpoint::Unix(address.to_string())
+    } else {
+      Endpoint::Tcp(address.to_string(), port.unwrap_or(6600))
+    }
+  }
+}
+
+
+/// The metadata of a song as reported by MPD's `currentsong` command.
+///
+/// MPD emits one `key: value` line per tag value, so a song with
+/// multiple artists yields several `Artist:` lines which we collect
+/// into [`artists`](Self::artists).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Song {
+  /// The song's path relative to the music directory.
+  pub file: String,
+  /// The artist(s) the song is attributed to.
+  pub artists: Vec<String>,
+  /// The song's title.
+  pub title: Option<String>,
+  /// The album the song belongs to.
+  pub album: Option<String>,
+  /// The song's release date.
+  pub date: Option<String>,
+  /// The song's track number.
+  pub track: Option<String>,
+}
+
+impl Song {
+  /// Assemble a song from the `key: value` pairs of a `currentsong`
+  /// response, returning `None` when no song is playing (i.e. no
+  /// `file` was reported).
+  fn from_pairs(pairs: Vec<(String, String)>) -> Option<Self> {
+    let mut song = Song::default();
+    let mut playing = false;
+    for (key, value) in pairs {
+      match key.as_str() {
+        "file" => {
+          song.file = value;
+          playing = true;
+        },
+        "Artist" => song.artists.push(value),
+        "Title" => song.title = Some(value),
+        "Album" => song.album = Some(value),
+        "Date" => song.date = Some(value),
+        "Track" => song.track = Some(value),
+        _ => (),
+      }
+    }
+    playing.then_some(song)
+  }
+
+  /// The basename of the song's file, serving as the fallback for any
+  /// tag that is missing.
+  fn basename(&self) -> &str {
+    self.file.rsplit('/').next().unwrap_or(&self.file)
+  }
+
+  /// Render a template, substituting `{tag}` placeholders (`{artist}`,
+  /// `{title}`, `{album}`, `{date}`, `{track}`, `{file}`) with the
+  /// corresponding values. Multiple artists are joined with
+  /// `artist_separator`. Any missing or unknown tag falls back to the
+  /// file's basename; an unterminated `{` is emitted verbatim.
+  pub(crate) fn render(&self, template: &str, artist_separator: &str) -> String {
+    let fallback = self.basename();
+    let artist = (!self.artists.is_empty()).then(|| self.artists.join(artist_separator));
+    let value = |tag: &str| -> String {
+      match tag {
+        "artist" => artist.clone(),
+        "title" => self.title.clone(),
+        "album" => self.album.clone(),
+        "date" => self.date.clone(),
+        "track" => self.track.clone(),
+        "file" => Some(self.file.clone()),
+        _ => None,
+      }
+      .unwrap_or_else(|| fallback.to_string())
+    };
+
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+      let () = out.push_str(&rest[..start]);
+      rest = &rest[start + 1..];
+      if let Some(end) = rest.find('}') {
+        let () = out.push_str(&value(&rest[..end]));
+        rest = &rest[end + 1..];
+      } else {
+        let () = out.push('{');
+        break
+      }
+    }
+    let () = out.push_str(rest);
+    out
+  }
+}
+
+
+/// Either end of an MPD connection, abstracting over the transport.
+enum Stream {
+  Tcp(TcpStream),
+  Unix(UnixStream),
+}
+
+impl Stream {
+  fn try_clone(&self) -> Result<Self> {
+    let stream = match self {
+      Stream::Tcp(stream) => Stream::Tcp(stream.try_clone()?),
+      Stream::Unix(stream) => Stream::Unix(stream.try_clone()?),
+    };
+    Ok(stream)
+  }
+}
+
+impl Read for Stream {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    match self {
+      Stream::Tcp(stream) => stream.read(buf),
+      Stream::Unix(stream) => stream.read(buf),
+    }
+  }
+}
+
+impl Write for Stream {
+  fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    match self {
+      Stream::Tcp(stream) => stream.write(buf),
+      Stream::Unix(stream) => stream.write(buf),
+    }
+  }
+
+  fn flush(&mut self) -> std::io::Result<()> {
+    match self {
+      Stream::Tcp(stream) => stream.flush(),
+      Stream::Unix(stream) => stream.flush(),
+    }
+  }
+}
+
+
+/// A connection to an MPD daemon.
+pub(crate) struct Client {
+  reader: BufReader<Stream>,
+  writer: Stream,
+}
+
+impl Client {
+  /// Connect to the MPD daemon at the given endpoint and consume its
+  /// greeting.
+  pub(crate) fn connect(endpoint: &Endpoint) -> Result<Self> {
+    let stream = match endpoint {
+      Endpoint::Tcp(host, port) => TcpStream::connect((host.as_str(), *port))
+        .map(Stream::Tcp)
+        .with_context(|| format!("failed to connect to MPD at {host}:{port}")),
+      Endpoint::Unix(path) => UnixStream::connect(Path::new(path))
+        .map(Stream::Unix)
+        .with_context(|| format!("failed to connect to MPD socket `{path}`")),
+    }?;
+
+    let writer = stream.try_clone().context("failed to clone MPD connection")?;
+    let mut slf = Self {
+      reader: BufReader::new(stream),
+      writer,
+    };
+
+    let greeting = slf.read_line().context("failed to read MPD greeting")?;
+    ensure!(
+      greeting.starts_with("OK MPD "),
+      "unexpected MPD greeting: `{greeting}`"
+    );
+    Ok(slf)
+  }
+
+  /// Read a single line, stripping the trailing newline.
+  fn read_line(&mut self) -> Result<String> {
+    let mut line = String::new();
+    let len = self
+      .reader
+      .read_line(&mut line)
+      .context("failed to read from MPD connection")?;
+    ensure!(len != 0, "MPD connection closed unexpectedly");
+    let () = line.truncate(line.trim_end_matches('\n').len());
+    Ok(line)
+  }
+
+  /// Send a command and collect the `key: value` pairs of its response.
+  pub(crate) fn command(&mut self, command: &str) -> Result<Vec<(String, String)>> {
+    let () = self
+      .writer
+      .write_all(format!("{command}\n").as_bytes())
+      .with_context(|| format!("failed to send `{command}` to MPD"))?;
+    let () = self
+      .writer
+      .flush()
+      .with_context(|| format!("failed to flush `{command}` to MPD"))?;
+
+    let mut pairs = Vec::new();
+    loop {
+      let line = self.read_line()?;
+      if line == "OK" {
+        break
+      }
+      if let Some(error) = line.strip_prefix("ACK ") {
+        bail!("MPD reported an error for `{command}`: {error}")
+      }
+      if let Some((key, value)) = line.split_once(": ") {
+        let () = pairs.push((key.to_string(), value.to_string()));
+      }
+    }
+    Ok(pairs)
+  }
+
+  /// Authenticate with the daemon using the given password.
+  pub(crate) fn password(&mut self, password: &str) -> Result<()> {
+    let _pairs = self.command(&format!("password \"{password}\""))?;
+    Ok(())
+  }
+
+  /// Block until MPD reports a change in the given subsystem.
+  pub(crate) fn idle(&mut self, subsystem: &str) -> Result<()> {
+    let _pairs = self.command(&format!("idle {subsystem}"))?;
+    Ok(())
+  }
+
+  /// Query the currently playing song, returning its metadata if any.
+  pub(crate) fn current_song(&mut self) -> Result<Option<Song>> {
+    let pairs = self.command("currentsong")?;
+    Ok(Song::from_pairs(pairs))
+  }
+
+  /// Read the embedded cover art of the song at `uri` via
+  /// `readpicture`, or `None` if the song carries none.
+  pub(crate) fn read_picture(&mut self, uri: &str) -> Result<Option<Vec<u8>>> {
+    self.binary_command("readpicture", uri)
+  }
+
+  /// Read the cover art accompanying the song at `uri` via `albumart`
+  /// (typically a `cover.*` file next to it), or `None` if there is
+  /// none.
+  pub(crate) fn albumart(&mut self, uri: &str) -> Result<Option<Vec<u8>>> {
+    self.binary_command("albumart", uri)
+  }
+
+  /// Drive one of MPD's binary commands (`albumart`/`readpicture`),
+  /// which stream their payload in chunks. Each response carries a
+  /// `size:` (total length), a `binary: <len>` header, exactly `<len>`
+  /// bytes, and a terminating `OK`; we re-issue the command with a
+  /// growing offset until `size` bytes have been collected. A missing
+  /// picture is reported by MPD as an `ACK` and surfaces as `None`.
+  fn binary_command(&mut self, command: &str, uri: &str) -> Result<Option<Vec<u8>>> {
+    let mut data = Vec::new();
+    loop {
+      let () = self
+        .writer
+        .write_all(format!("{command} \"{uri}\" {}\n", data.len()).as_bytes())
+        .with_context(|| format!("failed to send `{command}` to MPD"))?;
+      let () = self
+        .writer
+        .flush()
+        .with_context(|| format!("failed to flush `{command}` to MPD"))?;
+
+      let mut size = None;
+      let mut chunk = None;
+      loop {
+        let line = self.read_line()?;
+        if line == "OK" {
+          break
+        }
+        if line.starts_with("ACK ") {
+          // The song has no cover art associated with it.
+          return Ok(None)
+        }
+        match line.split_once(": ") {
+          Some(("size", value)) => {
+            size = Some(value.parse::<usize>().context("failed to parse `size` from MPD")?)
+          },
+          Some(("binary", value)) => {
+            chunk = Some(value.parse::<usize>().context("failed to parse `binary` from MPD")?);
+            break
+          },
+          _ => (),
+        }
+      }
+
+      let chunk = match chunk {
+        Some(chunk) => chunk,
+        // No `binary` header means an empty response; we are done.
+        None => break,
+      };
+
+      let offset = data.len();
+      let () = data.resize(offset + chunk, 0);
+      let () = self
+        .reader
+        .read_exact(&mut data[offset..])
+        .context("failed to read cover art chunk from MPD")?;
+
+      // The chunk is followed by a newline and the terminating `OK`.
+      let mut newline = [0u8; 1];
+      let () = self
+        .reader
+        .read_exact(&mut newline)
+        .context("failed to read cover art chunk terminator from MPD")?;
+      let terminator = self.read_line()?;
+      ensure!(
+        terminator == "OK",
+        "unexpected cover art response terminator: `{terminator}`"
+      );
+
+      match size {
+        Some(size) if data.len() < size => continue,
+        _ => break,
+      }
+    }
+
+    Ok((!data.is_empty()).then_some(data))
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+
+  /// Check that a `currentsong` response is parsed into song metadata,
+  /// collecting multiple `Artist` lines.
+  #[test]
+  fn song_parsing() {
+    let pairs = [
+      ("file", "by-artist/various/adele_-_someone_like_you.opus"),
+      ("Artist", "Adele"),
+      ("Artist", "Someone"),
+      ("Title", "Someone Like You"),
+      ("Album", "21"),
+      ("Date", "2011"),
+    ]
+    .map(|(key, value)| (key.to_string(), value.to_string()))
+    .to_vec();
+
+    let song = Song::from_pairs(pairs).unwrap();
+    assert_eq!(song.artists, ["Adele", "Someone"]);
+    assert_eq!(song.title.as_deref(), Some("Someone Like You"));
+    assert!(Song::from_pairs(Vec::new()).is_none());
+  }
+
+  /// Make sure that templates substitute known tags and fall back to
+  /// the file's basename for missing ones.
+  #[test]
+  fn template_rendering() {
+    let song = Song {
+      file: "by-artist/various/adele_-_someone_like_you.opus".to_string(),
+      artists: vec!["Adele".to_string(), "Someone".to_string()],
+      title: Some("Someone Like You".to_string()),
+      album: Some("21".to_string()),
+      date: None,
+      track: None,
+    };
+
+    assert_eq!(
+      song.render("{artist} – {title}", ", "),
+      "Adele, Someone – Someone Like You"
+    );
+    // A missing tag falls back to the basename.
+    assert_eq!(song.render("{date}", ", "), "adele_-_someone_like_you.opus");
+  }
+}